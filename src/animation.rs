@@ -0,0 +1,138 @@
+//! Frame-stepped animations for the keypad's LED matrix.
+//!
+//! Animations used to run as long blocking loops inside `run_display` (100 frames x a
+//! blocking delay each), starving the keypad-scan and HID tasks for the whole duration. Instead,
+//! an [`Animation`] renders exactly one frame per call and reports how long to wait before the
+//! next one, so the RTIC display task can reschedule itself with `spawn_after` and let other
+//! tasks run in between frames.
+
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    mono_font::{ascii::FONT_5X8, MonoTextStyle},
+    pixelcolor::Rgb888,
+    prelude::*,
+    text::{Baseline, Text, TextStyleBuilder},
+};
+use embedded_hal::blocking::i2c::{Read, Write};
+use rtic::time::duration::Milliseconds;
+use tinybmp::Bmp;
+
+use crate::display::NeoTrellisDisplay;
+
+/// Renders one animation, one frame at a time.
+pub trait Animation<I2C>
+where
+    I2C: Read + Write,
+{
+    /// Renders exactly one frame to `display` and returns the delay until the next frame
+    /// should be rendered, or `None` once the animation has finished.
+    fn next_frame(&mut self, display: &mut NeoTrellisDisplay<I2C>) -> Option<Milliseconds>;
+}
+
+fn apply_alpha(value: u8, alpha: u32) -> u8 {
+    let value = value as u32;
+    (if alpha < 50 {
+        value * alpha / 50
+    } else {
+        value * (100 - alpha) / 50
+    }) as u8
+}
+
+fn convert_color(color: Rgb888, alpha: u32) -> Rgb888 {
+    Rgb888::new(
+        apply_alpha(color.r(), alpha),
+        apply_alpha(color.g(), alpha),
+        apply_alpha(color.b(), alpha),
+    )
+}
+
+/// Fades a bitmap in and out over `NUM_FRAMES` steps.
+pub struct BreathingEffect {
+    bmp: Bmp<'static, Rgb888>,
+    frame: u32,
+    time_per_frame_ms: u32,
+}
+
+impl BreathingEffect {
+    const NUM_FRAMES: u32 = 100;
+
+    pub fn new(bmp: Bmp<'static, Rgb888>, total_time_ms: u32) -> Self {
+        Self {
+            bmp,
+            frame: 0,
+            time_per_frame_ms: total_time_ms / Self::NUM_FRAMES,
+        }
+    }
+}
+
+impl<I2C> Animation<I2C> for BreathingEffect
+where
+    I2C: Read + Write,
+{
+    fn next_frame(&mut self, display: &mut NeoTrellisDisplay<I2C>) -> Option<Milliseconds> {
+        if self.frame >= Self::NUM_FRAMES {
+            return None;
+        }
+
+        display.clear(Rgb888::BLACK).unwrap();
+        display
+            .draw_iter(
+                self.bmp
+                    .pixels()
+                    .map(|pixel| Pixel(pixel.0, convert_color(pixel.1, self.frame))),
+            )
+            .unwrap();
+        display.flush().unwrap();
+
+        self.frame += 1;
+        Some(Milliseconds(self.time_per_frame_ms))
+    }
+}
+
+/// Scrolls a line of text across the display, one column per frame.
+pub struct ScrollText {
+    text: &'static str,
+    offset: usize,
+    max_offset: usize,
+}
+
+impl ScrollText {
+    const TEXT_WIDTH: usize = 5;
+    const FRAME_DELAY_MS: u32 = 200;
+
+    pub fn new(text: &'static str) -> Self {
+        Self {
+            text,
+            offset: 0,
+            max_offset: text.len() * Self::TEXT_WIDTH,
+        }
+    }
+}
+
+impl<I2C> Animation<I2C> for ScrollText
+where
+    I2C: Read + Write,
+{
+    fn next_frame(&mut self, display: &mut NeoTrellisDisplay<I2C>) -> Option<Milliseconds> {
+        if self.offset >= self.max_offset {
+            return None;
+        }
+
+        let character_style = MonoTextStyle::new(&FONT_5X8, Rgb888::WHITE);
+        let text_style = TextStyleBuilder::new().baseline(Baseline::Bottom).build();
+
+        display.clear(Rgb888::BLACK).unwrap();
+        Text::with_text_style(
+            self.text,
+            Point::new(-(self.offset as i32), 7),
+            character_style,
+            text_style,
+        )
+        .draw(display)
+        .unwrap();
+        display.flush().unwrap();
+
+        self.offset += 1;
+        Some(Milliseconds(Self::FRAME_DELAY_MS))
+    }
+}
@@ -17,19 +17,281 @@ pub enum EventType {
 
 pub struct KeyEvent {
     pub usb_scan_code: u8,
+    pub modifier: u8,
     pub event_type: EventType,
 }
 
-impl From<neotrellis::Edge> for EventType {
-    fn from(edge: neotrellis::Edge) -> Self {
-        match edge {
-            neotrellis::Edge::Falling => Self::KeyUp,
-            neotrellis::Edge::Rising => Self::KeyDown,
-            _ => unimplemented!(),
+/// Maximum number of simultaneously-held USB scan codes reported in a single HID report
+/// (6-key rollover, matching the boot keyboard protocol).
+const MAX_ROLLOVER: usize = 6;
+
+/// The set of USB scan codes and modifier bits currently held down, built up by feeding
+/// [`KeyEvent`]s from [`NeoTrellisDisplay::process_events`] into [`PressedKeys::apply`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct PressedKeys {
+    pub modifier: u8,
+    pub keycodes: heapless::Vec<u8, MAX_ROLLOVER>,
+    /// Number of currently-held keys asserting each modifier bit, indexed by bit position.
+    /// `modifier` only clears a bit once its refcount drops to zero, so two keys mapped to the
+    /// same modifier (e.g. both shifts) don't let releasing one drop it out from under the other.
+    modifier_refs: [u8; 8],
+}
+
+impl PressedKeys {
+    pub const fn new() -> Self {
+        Self {
+            modifier: 0,
+            keycodes: heapless::Vec::new(),
+            modifier_refs: [0; 8],
+        }
+    }
+
+    /// Folds a `KeyEvent` into the pressed set, setting/clearing its modifier bit and
+    /// adding/removing its scan code. Extra keycodes beyond the rollover limit are dropped.
+    ///
+    /// This relies on every `KeyDown` reaching here being matched by exactly one later `KeyUp`
+    /// for the same key — i.e. on [`Debouncer`] only emitting confirmed transitions rather than
+    /// ever dropping one, or a key's refcounted modifier bits and scan code latch on forever.
+    pub fn apply(&mut self, event: &KeyEvent) {
+        match event.event_type {
+            EventType::KeyDown => {
+                for bit in 0..8 {
+                    if event.modifier & (1 << bit) != 0 {
+                        self.modifier_refs[bit] += 1;
+                        self.modifier |= 1 << bit;
+                    }
+                }
+                if event.usb_scan_code != 0 && !self.keycodes.contains(&event.usb_scan_code) {
+                    let _ = self.keycodes.push(event.usb_scan_code);
+                }
+            }
+            EventType::KeyUp => {
+                for bit in 0..8 {
+                    if event.modifier & (1 << bit) != 0 {
+                        self.modifier_refs[bit] = self.modifier_refs[bit].saturating_sub(1);
+                        if self.modifier_refs[bit] == 0 {
+                            self.modifier &= !(1 << bit);
+                        }
+                    }
+                }
+                if let Some(pos) = self
+                    .keycodes
+                    .iter()
+                    .position(|&code| code == event.usb_scan_code)
+                {
+                    self.keycodes.remove(pos);
+                }
+            }
         }
     }
 }
 
+impl Default for PressedKeys {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Number of physical keys covered by the keymap (4 NeoTrellis devices x 16 keys each).
+pub const NUM_KEYS: usize = 64;
+
+/// Number of layers held by a [`Layout`]. Layer 0 is the base layer and is always active.
+pub const NUM_LAYERS: usize = 4;
+
+/// A single entry in a keymap layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Action {
+    /// Emit this USB HID usage code while the key is held.
+    KeyCode(u8),
+    /// Set these bits (a mask, e.g. `0x02` for left shift) in the HID report's modifier byte
+    /// while the key is held.
+    Modifier(u8),
+    /// Activate `layer` for as long as this key is held.
+    MomentaryLayer(usize),
+    /// Flip `layer` between active/inactive each time this key is pressed.
+    ToggleLayer(usize),
+    /// Fall through to the same index on the layer below.
+    Transparent,
+}
+
+/// Resolves physical key presses against a stack of layers into HID scan codes and modifier
+/// bits, tracking which layers are currently active.
+///
+/// Modeled on keyberon's layout engine: [`Action::MomentaryLayer`] pushes a layer for as long as
+/// the key is held, [`Action::ToggleLayer`] flips a layer on/off, and [`Action::Transparent`]
+/// falls through to the layer below until a concrete action is found.
+pub struct Layout {
+    layers: [[Action; NUM_KEYS]; NUM_LAYERS],
+    /// Stack of layers activated by currently-held `MomentaryLayer` keys, most recent last.
+    momentary_stack: heapless::Vec<usize, NUM_LAYERS>,
+    toggled_layers: [bool; NUM_LAYERS],
+    /// Action each currently-pressed physical key resolved to, so releasing it later undoes
+    /// the right thing even if the active layer has since changed.
+    pressed: [Action; NUM_KEYS],
+}
+
+impl Layout {
+    pub fn new(layers: [[Action; NUM_KEYS]; NUM_LAYERS]) -> Self {
+        Self {
+            layers,
+            momentary_stack: heapless::Vec::new(),
+            toggled_layers: [false; NUM_LAYERS],
+            pressed: [Action::Transparent; NUM_KEYS],
+        }
+    }
+
+    fn active_layer(&self) -> usize {
+        self.momentary_stack.last().copied().unwrap_or_else(|| {
+            self.toggled_layers
+                .iter()
+                .enumerate()
+                .rev()
+                .find(|(_, &toggled)| toggled)
+                .map(|(layer, _)| layer)
+                .unwrap_or(0)
+        })
+    }
+
+    fn resolve(&self, index: usize) -> Action {
+        let mut layer = self.active_layer();
+        loop {
+            match self.layers[layer][index] {
+                Action::Transparent if layer > 0 => layer -= 1,
+                action => return action,
+            }
+        }
+    }
+
+    /// Feeds a physical key-down event into the layout, returning the `(usb_scan_code,
+    /// modifier)` pair it resolved to.
+    pub fn key_down(&mut self, index: usize) -> (u8, u8) {
+        let action = self.resolve(index);
+        self.pressed[index] = action;
+        match action {
+            Action::KeyCode(code) => (code, 0),
+            Action::Modifier(bit) => (0, bit),
+            Action::MomentaryLayer(layer) => {
+                let _ = self.momentary_stack.push(layer);
+                (0, 0)
+            }
+            Action::ToggleLayer(layer) => {
+                self.toggled_layers[layer] = !self.toggled_layers[layer];
+                (0, 0)
+            }
+            Action::Transparent => (0, 0),
+        }
+    }
+
+    /// Feeds a physical key-up event into the layout, returning the `(usb_scan_code, modifier)`
+    /// pair that was released.
+    pub fn key_up(&mut self, index: usize) -> (u8, u8) {
+        let action = core::mem::replace(&mut self.pressed[index], Action::Transparent);
+        match action {
+            Action::KeyCode(code) => (code, 0),
+            Action::Modifier(bit) => (0, bit),
+            Action::MomentaryLayer(layer) => {
+                if let Some(pos) = self.momentary_stack.iter().rposition(|&l| l == layer) {
+                    self.momentary_stack.remove(pos);
+                }
+                (0, 0)
+            }
+            Action::ToggleLayer(_) | Action::Transparent => (0, 0),
+        }
+    }
+
+    /// Overwrites a single keymap entry, e.g. in response to a host `SetKeymap` command.
+    pub fn set_action(&mut self, layer: usize, index: usize, action: Action) -> Result<(), Error> {
+        if layer >= NUM_LAYERS || index >= NUM_KEYS {
+            return Err(Error::OutOfBoundsCoordinate);
+        }
+        self.layers[layer][index] = action;
+        Ok(())
+    }
+
+    pub fn layers(&self) -> &[[Action; NUM_KEYS]; NUM_LAYERS] {
+        &self.layers
+    }
+
+    /// Reads a single keymap entry, e.g. in response to a host `GetKeymap` command.
+    pub fn action_at(&self, layer: usize, index: usize) -> Option<Action> {
+        self.layers.get(layer)?.get(index).copied()
+    }
+}
+
+impl Default for Layout {
+    /// A single-layer identity keymap: key `i` emits the same USB scan code
+    /// (`i + 4`) that `process_events` used to hardcode.
+    fn default() -> Self {
+        let mut layers = [[Action::Transparent; NUM_KEYS]; NUM_LAYERS];
+        for (index, action) in layers[0].iter_mut().enumerate() {
+            *action = Action::KeyCode(index as u8 + 4);
+        }
+        Self::new(layers)
+    }
+}
+
+/// Length, in milliseconds, of the lockout window following a confirmed transition.
+const DEFAULT_DEBOUNCE_LOCKOUT_MS: u8 = 5;
+
+/// Cyclic debouncer for the NeoTrellis key-edge FIFO.
+///
+/// The FIFO delivers one edge per physical transition rather than a periodic level sample, so a
+/// clean keypress is a *single* `Rising` edge — there is no run of agreeing scans to count. This
+/// confirms a transition on its first edge (so the keyboard is responsive) and then locks that
+/// key out for `lockout_window_ms` milliseconds, swallowing the spurious `Rising`/`Falling` pairs
+/// mechanical bounce produces while the contacts settle. The lockout is tracked in milliseconds
+/// rather than edges: a bounce always arrives within a few milliseconds of the edge that
+/// triggered it, while a genuine opposite transition (the user actually releasing the key) is
+/// held far longer than the lockout window and so is never mistaken for bounce. [`Self::tick`]
+/// must be called once per scan, independent of whether that scan saw any edges at all, so a key
+/// that never bounces isn't left locked out.
+pub struct Debouncer {
+    state: [bool; NUM_KEYS],
+    lockout_ms: [u8; NUM_KEYS],
+    lockout_window_ms: u8,
+}
+
+impl Debouncer {
+    pub const fn new(lockout_window_ms: u8) -> Self {
+        Self {
+            state: [false; NUM_KEYS],
+            lockout_ms: [0; NUM_KEYS],
+            lockout_window_ms,
+        }
+    }
+
+    /// Advances the debounce clock by `elapsed_ms`, counting down every key's lockout window.
+    /// Must be called once per scan, not once per edge.
+    pub fn tick(&mut self, elapsed_ms: u8) {
+        for lockout in self.lockout_ms.iter_mut() {
+            *lockout = lockout.saturating_sub(elapsed_ms);
+        }
+    }
+
+    /// Feeds one observed edge (`true` = pressed) for the key at `index`. Returns
+    /// `Some(pressed)` when this edge confirms a logical transition, `None` if the key is still
+    /// within the lockout window following a prior confirmed transition.
+    pub fn debounce(&mut self, index: usize, pressed: bool) -> Option<bool> {
+        if self.lockout_ms[index] > 0 {
+            return None;
+        }
+
+        if pressed == self.state[index] {
+            return None;
+        }
+
+        self.state[index] = pressed;
+        self.lockout_ms[index] = self.lockout_window_ms;
+        Some(pressed)
+    }
+}
+
+impl Default for Debouncer {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEBOUNCE_LOCKOUT_MS)
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     Device(neotrellis::Error),
@@ -72,6 +334,8 @@ fn index_for_device_and_pixel(device_idx: u32, pix_idx: u32) -> usize {
 pub struct NeoTrellisDisplay<I2C: Read + Write> {
     devices: [NeoTrellis<I2C>; 4],
     framebuffer: [pixelcolor::Rgb888; 64],
+    layout: Layout,
+    debouncer: Debouncer,
 }
 
 impl<I2C> OriginDimensions for NeoTrellisDisplay<I2C>
@@ -87,10 +351,12 @@ impl<I2C> NeoTrellisDisplay<I2C>
 where
     I2C: Read + Write,
 {
-    pub fn new(devices: [NeoTrellis<I2C>; 4]) -> Self {
+    pub fn new(devices: [NeoTrellis<I2C>; 4], layout: Layout) -> Self {
         Self {
             devices,
             framebuffer: [pixelcolor::Rgb888::default(); 64],
+            layout,
+            debouncer: Debouncer::default(),
         }
     }
 
@@ -107,14 +373,21 @@ where
         Ok(())
     }
 
+    /// Scans every device for pending key-edge events and feeds confirmed transitions into the
+    /// keymap. `elapsed_ms` is the time since this was last called (e.g. the caller's fixed scan
+    /// period) and drives the debouncer's lockout window; it must be supplied on every call,
+    /// including ones where no edges end up being reported.
     pub fn process_events<
         Delay: embedded_hal::blocking::delay::DelayUs<u32>,
         Handler: FnMut(KeyEvent),
     >(
         &mut self,
         delay: &mut Delay,
+        elapsed_ms: u8,
         mut event_handler: Handler,
     ) -> Result<(), Error> {
+        self.debouncer.tick(elapsed_ms);
+
         let mut any_updates = false;
         for (dev_idx, dev) in self.devices.iter_mut().enumerate() {
             let mut keypad = dev.keypad();
@@ -128,23 +401,30 @@ where
                             event.key.into(),
                         );
 
-                        match event.event {
-                            neotrellis::Edge::Falling => {
-                                self.framebuffer[index] = pixelcolor::Rgb888::BLACK;
-                                any_updates = true;
-                            }
-                            neotrellis::Edge::Rising => {
-                                self.framebuffer[index] = pixelcolor::Rgb888::WHITE;
-                                any_updates = true;
-                            }
-                            _ => {}
+                        let observed_pressed = matches!(event.event, neotrellis::Edge::Rising);
+                        if let Some(pressed) = self.debouncer.debounce(index, observed_pressed) {
+                            self.framebuffer[index] = if pressed {
+                                pixelcolor::Rgb888::WHITE
+                            } else {
+                                pixelcolor::Rgb888::BLACK
+                            };
+                            any_updates = true;
+
+                            let (usb_scan_code, modifier) = if pressed {
+                                self.layout.key_down(index)
+                            } else {
+                                self.layout.key_up(index)
+                            };
+                            event_handler(KeyEvent {
+                                usb_scan_code,
+                                modifier,
+                                event_type: if pressed {
+                                    EventType::KeyDown
+                                } else {
+                                    EventType::KeyUp
+                                },
+                            });
                         }
-                        let event = KeyEvent {
-                            // TODO(javier): Use proper scan code table and remap pixels
-                            usb_scan_code: index as u8 + 4,
-                            event_type: event.event.into(),
-                        };
-                        event_handler(event);
                     }
                     None => {
                         defmt::error!("Incomplete read of events for keypad device!");
@@ -160,6 +440,45 @@ where
         Ok(())
     }
 
+    /// Overwrites a single keymap entry, e.g. in response to a host `SetKeymap` command.
+    pub fn set_keymap_action(
+        &mut self,
+        layer: usize,
+        index: usize,
+        action: Action,
+    ) -> Result<(), Error> {
+        self.layout.set_action(layer, index, action)
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Replaces the whole keymap, e.g. when restoring a saved [`crate::nvstate::Config`].
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    pub fn framebuffer(&self) -> &[pixelcolor::Rgb888; NUM_KEYS] {
+        &self.framebuffer
+    }
+
+    /// Replaces the whole framebuffer without flushing it, e.g. when restoring a saved
+    /// [`crate::nvstate::Config`]; call [`Self::flush`] afterwards to push it to the hardware.
+    pub fn set_framebuffer(&mut self, framebuffer: [pixelcolor::Rgb888; NUM_KEYS]) {
+        self.framebuffer = framebuffer;
+    }
+
+    /// Sets a single key's framebuffer color and flushes it out, e.g. in response to a host
+    /// `SetPixel` command.
+    pub fn set_pixel(&mut self, index: usize, color: pixelcolor::Rgb888) -> Result<(), Error> {
+        if index >= NUM_KEYS {
+            return Err(Error::OutOfBoundsCoordinate);
+        }
+        self.framebuffer[index] = color;
+        self.flush()
+    }
+
     pub fn flush(&mut self) -> Result<(), Error> {
         for (i, dev) in self.devices.iter_mut().enumerate() {
             let index = i * 16;
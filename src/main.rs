@@ -1,25 +1,17 @@
 #![no_std]
 #![no_main]
 
+mod animation;
 mod display;
+mod nvstate;
+mod protocol;
 
-use core::convert::TryFrom;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 use defmt_rtt as _;
 use panic_probe as _;
 
-use embedded_graphics::{
-    draw_target::DrawTarget,
-    mono_font::{ascii::FONT_5X8, MonoTextStyle},
-    pixelcolor::Rgb888,
-    prelude::*,
-    text::{Baseline, Text, TextStyleBuilder},
-};
 use nrf52840_hal as _;
-use tinybmp::Bmp;
-
-use display::NeoTrellisDisplay;
 
 #[defmt::panic_handler]
 fn panic() -> ! {
@@ -31,87 +23,21 @@ defmt::timestamp!("{=usize}", {
     COUNT.fetch_add(1, Ordering::Relaxed)
 });
 
-fn apply_breathing_effect<I2C, TIMER>(
-    display: &mut NeoTrellisDisplay<I2C>,
-    timer: &mut TIMER,
-    bmp: &Bmp<'_, Rgb888>,
-    time_ms: u32,
-) where
-    I2C: embedded_hal::blocking::i2c::Write + embedded_hal::blocking::i2c::Read,
-    TIMER: embedded_hal::blocking::delay::DelayMs<u32>,
-{
-    const NUM_FRAMES: u32 = 100;
-
-    let time_per_frame = time_ms / NUM_FRAMES;
-
-    let apply_alpha = |value, alpha| {
-        let value = value as u32;
-        (if alpha < 50 {
-            value * alpha / 50
-        } else {
-            value * (100 - alpha) / 50
-        }) as u8
-    };
-
-    let convert_color = |color: Rgb888, alpha| {
-        Rgb888::new(
-            apply_alpha(color.r(), alpha),
-            apply_alpha(color.g(), alpha),
-            apply_alpha(color.b(), alpha),
-        )
-    };
-
-    for i in 0..NUM_FRAMES {
-        display.clear(Rgb888::BLACK).unwrap();
-        display
-            .draw_iter(
-                bmp.pixels()
-                    .map(|pixel| Pixel(pixel.0, convert_color(pixel.1, i))),
-            )
-            .unwrap();
-        display.flush().unwrap();
-
-        timer.delay_ms(time_per_frame);
-    }
-}
-
-fn scroll_text<T, TIMER>(display: &mut NeoTrellisDisplay<T>, timer: &mut TIMER, text: &str)
-where
-    T: embedded_hal::blocking::i2c::Write + embedded_hal::blocking::i2c::Read,
-    TIMER: embedded_hal::blocking::delay::DelayMs<u32>,
-{
-    const TEXT_WIDTH: usize = 5;
-
-    let character_style = MonoTextStyle::new(&FONT_5X8, Rgb888::WHITE);
-    let text_style = TextStyleBuilder::new().baseline(Baseline::Bottom).build();
-
-    let max_disp = text.len() * TEXT_WIDTH;
-    for i in 0..max_disp {
-        display.clear(Rgb888::BLACK).unwrap();
-        Text::with_text_style(
-            text,
-            Point::new(-i32::try_from(i).unwrap(), 7),
-            character_style,
-            text_style,
-        )
-        .draw(display)
-        .unwrap();
-        display.flush().unwrap();
-        timer.delay_ms(200u32);
-    }
-}
-
 #[rtic::app(device = nrf52840_hal::pac, peripherals = true, dispatchers = [USBD, QSPI, NFCT])]
 mod app {
-    use crate::scroll_text;
     use adafruit_neotrellis::NeoTrellis;
-    use embedded_graphics::pixelcolor::Rgb888;
+    use embedded_graphics::pixelcolor::{Rgb888, RgbColor};
     use nrf52840_hal::{self as _, gpio, pac, timer, twim};
     use shared_bus::BusManagerAtomicCheck as BusManager;
     use tinybmp::Bmp;
 
-    use crate::apply_breathing_effect;
-    use crate::display::NeoTrellisDisplay;
+    use crate::animation::{Animation, BreathingEffect, ScrollText};
+    use crate::display::{Layout, NeoTrellisDisplay, PressedKeys};
+    use crate::nvstate::{apply_config_to_display, config_from_display, NvState};
+    use crate::protocol::{
+        self, decode_host_message, encode_device_message, DeviceMessage, FrameReassembler,
+        HostMessage,
+    };
 
     use nrf52840_hal::clocks;
     use nrf52840_hal::usbd;
@@ -123,29 +49,86 @@ mod app {
     };
     use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
     use usbd_hid::hid_class::HIDClass;
+    use usbd_serial::SerialPort;
 
     use dwt_systick_monotonic::DwtSystick;
     use rtic::time::duration::Milliseconds;
 
     const MONO_HZ: u32 = 64_000_000; // 64 MHz
 
+    /// Period `keypad_scan` reschedules itself at; also the `elapsed_ms` fed to
+    /// `NeoTrellisDisplay::process_events` so its debouncer's lockout window counts down in
+    /// real milliseconds rather than edges.
+    const KEYPAD_SCAN_PERIOD_MS: u8 = 2;
+
     #[monotonic(binds = SysTick, default = true, priority = 8)]
     type MyMono = DwtSystick<MONO_HZ>;
 
+    type I2cBus = shared_bus::I2cProxy<'static, shared_bus::AtomicCheckMutex<twim::Twim<pac::TWIM0>>>;
+
+    /// The idle-screen demo loop: breathe the heart, then scroll the greeting, then repeat.
+    enum DemoAnimation {
+        Breathing(BreathingEffect),
+        ScrollText(ScrollText),
+    }
+
+    impl DemoAnimation {
+        fn next(&self, heart_bmp: &Bmp<'static, Rgb888>) -> Self {
+            match self {
+                DemoAnimation::Breathing(_) => {
+                    DemoAnimation::ScrollText(ScrollText::new("Hi There!!"))
+                }
+                DemoAnimation::ScrollText(_) => {
+                    DemoAnimation::Breathing(BreathingEffect::new(heart_bmp.clone(), 1000))
+                }
+            }
+        }
+
+        /// Builds the animation a host `PlayAnimation` command asked to play on demand.
+        fn from_kind(kind: protocol::AnimationKind, heart_bmp: &Bmp<'static, Rgb888>) -> Self {
+            match kind {
+                protocol::AnimationKind::Breathing => {
+                    DemoAnimation::Breathing(BreathingEffect::new(heart_bmp.clone(), 1000))
+                }
+                protocol::AnimationKind::ScrollText => {
+                    DemoAnimation::ScrollText(ScrollText::new("Hi There!!"))
+                }
+            }
+        }
+    }
+
+    impl Animation<I2cBus> for DemoAnimation {
+        fn next_frame(&mut self, display: &mut NeoTrellisDisplay<I2cBus>) -> Option<Milliseconds> {
+            match self {
+                DemoAnimation::Breathing(anim) => anim.next_frame(display),
+                DemoAnimation::ScrollText(anim) => anim.next_frame(display),
+            }
+        }
+    }
+
     #[local]
     struct Local {
-        timer: timer::Timer<pac::TIMER0>,
-        display: NeoTrellisDisplay<
-            shared_bus::I2cProxy<'static, shared_bus::AtomicCheckMutex<twim::Twim<pac::TWIM0>>>,
-        >,
         heart_bmp: Bmp<'static, Rgb888>,
         usb_device: UsbDevice<'static, usbd::Usbd<usbd::UsbPeripheral<'static>>>,
-        keycode_pingpong: bool,
+        last_pressed: PressedKeys,
+        reassembler: FrameReassembler,
+        current_animation: DemoAnimation,
+        /// Set whenever `SetKeymap`/`SetPixel` changes the in-RAM config; cleared by `Save`,
+        /// which is the only thing that actually erases and rewrites the flash page.
+        config_dirty: bool,
     }
 
     #[shared]
     struct Shared {
         hid_class: HIDClass<'static, usbd::Usbd<usbd::UsbPeripheral<'static>>>,
+        serial: SerialPort<'static, usbd::Usbd<usbd::UsbPeripheral<'static>>>,
+        timer: timer::Timer<pac::TIMER0>,
+        display: NeoTrellisDisplay<I2cBus>,
+        pressed_keys: PressedKeys,
+        nvstate: NvState,
+        /// Set by a host `PlayAnimation` command; `run_display` picks it up and switches to it
+        /// at the start of its next frame.
+        requested_animation: Option<protocol::AnimationKind>,
     }
 
     #[init(
@@ -190,13 +173,14 @@ mod app {
         let usb_bus_allocator = cx.local.usb_buf_alloc.as_ref().unwrap();
 
         let mut hid_class = HIDClass::new(usb_bus_allocator, KeyboardReport::desc(), 60);
+        let mut serial = SerialPort::new(usb_bus_allocator);
         let mut usb_device = UsbDeviceBuilder::new(usb_bus_allocator, UsbVidPid(0x5824, 0x27dd))
             .manufacturer("AllThingsEmbedded")
             .product("USB mouse")
             .serial_number("00000000")
             .device_class(0xef)
             .build();
-        usb_device.poll(&mut [&mut hid_class]);
+        usb_device.poll(&mut [&mut hid_class, &mut serial]);
 
         *cx.local.i2c_bus = Some(i2c);
         let i2c = cx.local.i2c_bus.as_mut().unwrap();
@@ -207,51 +191,196 @@ mod app {
             NeoTrellis::new(i2c.acquire_i2c(), &mut timer, Some(0x30)).unwrap(),
             NeoTrellis::new(i2c.acquire_i2c(), &mut timer, Some(0x31)).unwrap(),
         ];
-        let mut display = NeoTrellisDisplay::new(neotrellis_devs);
+        let mut nvstate = NvState::new(peripherals.NVMC);
+
+        let mut display = NeoTrellisDisplay::new(neotrellis_devs, Layout::default());
+        if let Some(config) = nvstate.load() {
+            apply_config_to_display(&mut display, &config);
+        }
         display.init().unwrap();
 
         usb_task::spawn().unwrap();
         run_display::spawn().unwrap();
+        keypad_scan::spawn().unwrap();
 
         (
-            Shared { hid_class },
-            Local {
+            Shared {
+                hid_class,
+                serial,
                 timer,
                 display,
+                pressed_keys: PressedKeys::new(),
+                nvstate,
+                requested_animation: None,
+            },
+            Local {
+                current_animation: DemoAnimation::Breathing(BreathingEffect::new(heart_bmp.clone(), 1000)),
                 heart_bmp,
                 usb_device,
-                keycode_pingpong: true,
+                last_pressed: PressedKeys::new(),
+                reassembler: FrameReassembler::new(),
+                config_dirty: false,
             },
             init::Monotonics(mono),
         )
     }
 
-    #[task(local = [usb_device], shared = [hid_class], priority = 3)]
+    #[task(local = [usb_device], shared = [hid_class, serial], priority = 3)]
     fn usb_task(mut cx: usb_task::Context) {
         let usb_dev = cx.local.usb_device;
-        cx.shared.hid_class.lock(|hid| {
-            if usb_dev.poll(&mut [hid]) {
+        (cx.shared.hid_class, cx.shared.serial).lock(|hid, serial| {
+            if usb_dev.poll(&mut [hid, serial]) {
                 hid_task::spawn().unwrap();
+                serial_task::spawn().unwrap();
             }
         });
         usb_task::spawn_after(Milliseconds(2u32)).unwrap();
     }
 
-    #[task(local = [keycode_pingpong], shared = [hid_class], priority = 2)]
+    /// Reads bytes off the CDC-ACM control channel, reassembles COBS frames and dispatches each
+    /// decoded `HostMessage` against the keymap/framebuffer, writing back one `DeviceMessage` per
+    /// frame as soon as it's decoded — a single bulk read can coalesce several host commands, and
+    /// each one needs its own reply or the host desyncs waiting for a response.
+    #[task(
+        local = [reassembler, config_dirty],
+        shared = [serial, display, nvstate, requested_animation],
+        priority = 2
+    )]
+    fn serial_task(mut cx: serial_task::Context) {
+        let mut buf = [0u8; protocol::MAX_FRAME_LEN];
+        let config_dirty = cx.local.config_dirty;
+        (
+            cx.shared.serial,
+            cx.shared.display,
+            cx.shared.nvstate,
+            cx.shared.requested_animation,
+        )
+            .lock(|serial, display, nvstate, requested_animation| {
+                let count = match serial.read(&mut buf) {
+                    Ok(count) => count,
+                    Err(UsbError::WouldBlock) => return,
+                    Err(err) => {
+                        defmt::warn!("serial_task: read error {:?}", err);
+                        return;
+                    }
+                };
+
+                cx.local.reassembler.feed(&buf[..count], |frame| {
+                    if let Some(message) = decode_host_message(frame) {
+                        let reply =
+                            dispatch(display, nvstate, config_dirty, requested_animation, message);
+                        if let Some(bytes) = encode_device_message(&reply) {
+                            let _ = serial.write(&bytes);
+                        }
+                    }
+                });
+            });
+    }
+
+    /// Applies one decoded `HostMessage` to the keypad, returning the reply to send back.
+    /// `SetKeymap`/`SetPixel` only mark `config_dirty`; the page is actually erased and
+    /// rewritten on an explicit `Save`, so uploading a whole keymap doesn't burn one flash
+    /// erase cycle per key.
+    fn dispatch(
+        display: &mut NeoTrellisDisplay<I2cBus>,
+        nvstate: &mut NvState,
+        config_dirty: &mut bool,
+        requested_animation: &mut Option<protocol::AnimationKind>,
+        message: HostMessage,
+    ) -> DeviceMessage {
+        match message {
+            HostMessage::SetKeymap {
+                layer,
+                index,
+                action,
+            } => match display.set_keymap_action(layer, index, action) {
+                Ok(()) => {
+                    *config_dirty = true;
+                    DeviceMessage::Ack
+                }
+                Err(_) => DeviceMessage::Error,
+            },
+            HostMessage::SetPixel { index, rgb } => {
+                let (r, g, b) = rgb;
+                match display.set_pixel(index, Rgb888::new(r, g, b)) {
+                    Ok(()) => {
+                        *config_dirty = true;
+                        DeviceMessage::Ack
+                    }
+                    Err(_) => DeviceMessage::Error,
+                }
+            }
+            HostMessage::GetKeymap { layer, index } => {
+                match display.layout().action_at(layer, index) {
+                    Some(action) => DeviceMessage::KeymapEntry {
+                        layer,
+                        index,
+                        action,
+                    },
+                    None => DeviceMessage::Error,
+                }
+            }
+            HostMessage::GetPixel { index } => match display.framebuffer().get(index) {
+                Some(color) => DeviceMessage::Pixel {
+                    index,
+                    rgb: (color.r(), color.g(), color.b()),
+                },
+                None => DeviceMessage::Error,
+            },
+            HostMessage::Save => {
+                if *config_dirty {
+                    nvstate.save(&config_from_display(display));
+                    *config_dirty = false;
+                }
+                DeviceMessage::Ack
+            }
+            HostMessage::GetConfig => DeviceMessage::Config {
+                num_layers: crate::display::NUM_LAYERS,
+            },
+            HostMessage::PlayAnimation { kind } => {
+                *requested_animation = Some(kind);
+                DeviceMessage::Ack
+            }
+        }
+    }
+
+    /// Polls the keypad for key-edge events, folds them into the shared `pressed_keys` set and
+    /// kicks off `hid_task` whenever that set actually changes.
+    #[task(local = [last_pressed], shared = [display, timer, pressed_keys], priority = 2)]
+    fn keypad_scan(mut cx: keypad_scan::Context) {
+        let mut changed = false;
+        (cx.shared.display, cx.shared.timer, cx.shared.pressed_keys).lock(
+            |display, timer, pressed_keys| {
+                display
+                    .process_events(timer, KEYPAD_SCAN_PERIOD_MS, |event| {
+                        pressed_keys.apply(&event)
+                    })
+                    .ok();
+                if *pressed_keys != *cx.local.last_pressed {
+                    *cx.local.last_pressed = pressed_keys.clone();
+                    changed = true;
+                }
+            },
+        );
+
+        if changed {
+            hid_task::spawn().unwrap();
+        }
+
+        keypad_scan::spawn_after(Milliseconds(KEYPAD_SCAN_PERIOD_MS as u32)).unwrap();
+    }
+
+    #[task(shared = [hid_class, pressed_keys], priority = 2)]
     fn hid_task(mut cx: hid_task::Context) {
-        let keycode_0 = 0x27;
-        let keycode = if *cx.local.keycode_pingpong {
-            keycode_0
-        } else {
-            0
-        };
-        defmt::info!("Sending keycode {}", keycode);
-        *cx.local.keycode_pingpong = !*cx.local.keycode_pingpong;
-        cx.shared.hid_class.lock(|hid| {
+        (cx.shared.pressed_keys, cx.shared.hid_class).lock(|pressed_keys, hid| {
+            let mut keycodes = [0u8; 6];
+            for (slot, code) in keycodes.iter_mut().zip(pressed_keys.keycodes.iter()) {
+                *slot = *code;
+            }
             let report = KeyboardReport {
-                modifier: 0,
+                modifier: pressed_keys.modifier,
                 leds: 0,
-                keycodes: [keycode, 0, 0, 0, 0, 0],
+                keycodes,
             };
             match hid.push_input(&report) {
                 Err(UsbError::WouldBlock) => defmt::warn!("hid_task: Would block"),
@@ -261,18 +390,32 @@ mod app {
         });
     }
 
-    #[task(local = [display, timer, heart_bmp], priority = 1)]
-    fn run_display(cx: run_display::Context) {
-        let timer = cx.local.timer;
-        let display = cx.local.display;
-        let heart_bmp = cx.local.heart_bmp;
-
-        // TODO(javier): Chunk these operations so that they keypad can be used concurrently
-        apply_breathing_effect(display, timer, heart_bmp, 1000);
-        scroll_text(display, timer, "Hi There!!");
-
-        defmt::info!("run_display finished");
-
-        run_display::spawn_after(Milliseconds(10u32)).ok();
+    /// Advances the idle-screen demo animation by exactly one frame, then reschedules itself
+    /// after the delay the animation asks for. This lets `keypad_scan` and the HID tasks run
+    /// in between frames instead of being starved for a whole animation's duration. If a host
+    /// `PlayAnimation` command came in since the last frame, switch to the requested animation
+    /// first.
+    #[task(
+        local = [current_animation, heart_bmp],
+        shared = [display, requested_animation],
+        priority = 1
+    )]
+    fn run_display(mut cx: run_display::Context) {
+        if let Some(kind) = cx.shared.requested_animation.lock(Option::take) {
+            *cx.local.current_animation = DemoAnimation::from_kind(kind, cx.local.heart_bmp);
+        }
+
+        let delay = cx
+            .shared
+            .display
+            .lock(|display| cx.local.current_animation.next_frame(display));
+
+        match delay {
+            Some(delay) => run_display::spawn_after(delay).unwrap(),
+            None => {
+                *cx.local.current_animation = cx.local.current_animation.next(cx.local.heart_bmp);
+                run_display::spawn_after(Milliseconds(10u32)).unwrap();
+            }
+        }
     }
 }
@@ -0,0 +1,153 @@
+//! Non-volatile persistence for the keymap and per-key LED colors, modeled on cheapsdo's
+//! NVState pattern: a `postcard`-encoded [`Config`] record, tagged with a magic/version header
+//! and a CRC, stored in a flash page reserved at the end of the nRF52840's internal flash.
+
+use embedded_graphics::pixelcolor::{Rgb888, RgbColor};
+use embedded_hal::blocking::i2c::{Read, Write};
+use nrf52840_hal::pac::NVMC;
+use serde::{Deserialize, Serialize};
+
+use crate::display::{Action, Layout, NeoTrellisDisplay, NUM_KEYS, NUM_LAYERS};
+
+/// Start address of the flash page reserved for persisted configuration: the last 4 KiB page
+/// of the nRF52840's 1 MiB internal flash.
+const CONFIG_PAGE_ADDR: u32 = 0x000F_F000;
+const PAGE_SIZE: usize = 4096;
+
+const MAGIC: u32 = 0x4B45_5054; // "KEPT"
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = 8; // magic: u32, version: u16, payload len: u16
+
+/// Everything that survives a reset: the keymap layers and the default framebuffer colors.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    pub keymap: [[Action; NUM_KEYS]; NUM_LAYERS],
+    pub framebuffer: [(u8, u8, u8); NUM_KEYS],
+}
+
+/// Snapshots a display's current keymap and framebuffer into a `Config` ready to be saved.
+pub fn config_from_display<I2C: Read + Write>(display: &NeoTrellisDisplay<I2C>) -> Config {
+    let mut framebuffer = [(0u8, 0u8, 0u8); NUM_KEYS];
+    for (slot, color) in framebuffer.iter_mut().zip(display.framebuffer().iter()) {
+        *slot = (color.r(), color.g(), color.b());
+    }
+
+    Config {
+        keymap: *display.layout().layers(),
+        framebuffer,
+    }
+}
+
+/// Restores a previously-saved `Config` onto a display's keymap and framebuffer.
+pub fn apply_config_to_display<I2C: Read + Write>(
+    display: &mut NeoTrellisDisplay<I2C>,
+    config: &Config,
+) {
+    display.set_layout(Layout::new(config.keymap));
+
+    let mut colors = [Rgb888::default(); NUM_KEYS];
+    for (slot, &(r, g, b)) in colors.iter_mut().zip(config.framebuffer.iter()) {
+        *slot = Rgb888::new(r, g, b);
+    }
+    display.set_framebuffer(colors);
+}
+
+/// CRC-32 (IEEE 802.3), computed bit-by-bit to avoid pulling in a lookup table.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Owns the NVMC peripheral and reads/writes the reserved configuration page.
+pub struct NvState {
+    nvmc: NVMC,
+}
+
+impl NvState {
+    pub fn new(nvmc: NVMC) -> Self {
+        Self { nvmc }
+    }
+
+    fn wait_ready(&self) {
+        while self.nvmc.ready.read().ready().is_busy() {}
+    }
+
+    fn erase_page(&mut self) {
+        self.nvmc.config.write(|w| w.wen().een());
+        self.wait_ready();
+        self.nvmc
+            .erasepage
+            .write(|w| unsafe { w.erasepage().bits(CONFIG_PAGE_ADDR) });
+        self.wait_ready();
+        self.nvmc.config.write(|w| w.wen().ren());
+    }
+
+    fn write_word(&mut self, offset: usize, word: u32) {
+        self.nvmc.config.write(|w| w.wen().wen());
+        self.wait_ready();
+        unsafe {
+            core::ptr::write_volatile((CONFIG_PAGE_ADDR as usize + offset) as *mut u32, word);
+        }
+        self.wait_ready();
+        self.nvmc.config.write(|w| w.wen().ren());
+    }
+
+    /// Attempts to load a previously-saved `Config` from flash, validating the magic, version
+    /// and CRC. Returns `None` if the page is blank or the stored record is corrupt.
+    pub fn load(&self) -> Option<Config> {
+        let base = CONFIG_PAGE_ADDR as *const u8;
+        let header = unsafe { core::slice::from_raw_parts(base, HEADER_LEN) };
+
+        let magic = u32::from_le_bytes(header[0..4].try_into().ok()?);
+        let version = u16::from_le_bytes(header[4..6].try_into().ok()?);
+        let len = u16::from_le_bytes(header[6..8].try_into().ok()?) as usize;
+        if magic != MAGIC || version != VERSION || len == 0 || len > PAGE_SIZE - HEADER_LEN - 4 {
+            return None;
+        }
+
+        let payload = unsafe { core::slice::from_raw_parts(base.add(HEADER_LEN), len) };
+        let stored_crc = unsafe {
+            let crc_bytes = core::slice::from_raw_parts(base.add(HEADER_LEN + len), 4);
+            u32::from_le_bytes(crc_bytes.try_into().ok()?)
+        };
+        if crc32(payload) != stored_crc {
+            return None;
+        }
+
+        postcard::from_bytes(payload).ok()
+    }
+
+    /// Erases the reserved page and rewrites it with `config`, computing a fresh CRC.
+    pub fn save(&mut self, config: &Config) {
+        let mut record = [0xffu8; PAGE_SIZE];
+        let encoded_len = match postcard::to_slice(config, &mut record[HEADER_LEN..]) {
+            Ok(bytes) => bytes.len(),
+            Err(_) => {
+                defmt::error!("nvstate: config too large to persist");
+                return;
+            }
+        };
+
+        let crc = crc32(&record[HEADER_LEN..HEADER_LEN + encoded_len]);
+        record[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        record[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        record[6..8].copy_from_slice(&(encoded_len as u16).to_le_bytes());
+        record[HEADER_LEN + encoded_len..HEADER_LEN + encoded_len + 4]
+            .copy_from_slice(&crc.to_le_bytes());
+
+        self.erase_page();
+        let total_len = HEADER_LEN + encoded_len + 4;
+        for (i, chunk) in record[..total_len].chunks(4).enumerate() {
+            let mut word_bytes = [0xffu8; 4];
+            word_bytes[..chunk.len()].copy_from_slice(chunk);
+            self.write_word(i * 4, u32::from_le_bytes(word_bytes));
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Wire protocol for the CDC-ACM configuration channel (see `usb_task`/`serial_task` in
+//! `main.rs`). Messages are `postcard`-encoded and COBS-framed so a single USB bulk transfer
+//! can be split or coalesced by the host without losing frame boundaries.
+
+use serde::{Deserialize, Serialize};
+
+use crate::display::Action;
+
+/// Largest COBS frame (encoded or decoded) this channel will handle.
+pub const MAX_FRAME_LEN: usize = 64;
+
+/// A command sent from the host to the keypad.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HostMessage {
+    /// Overwrite a single keymap entry: `layers[layer][index] = action`.
+    SetKeymap {
+        layer: usize,
+        index: usize,
+        action: Action,
+    },
+    /// Set the framebuffer color of a single key, identified by its flat pixel index.
+    SetPixel { index: usize, rgb: (u8, u8, u8) },
+    /// Read back a single keymap entry.
+    GetKeymap { layer: usize, index: usize },
+    /// Read back the framebuffer color of a single key.
+    GetPixel { index: usize },
+    /// Ask the device to report its current configuration.
+    GetConfig,
+    /// Play one of the built-in animations on demand.
+    PlayAnimation { kind: AnimationKind },
+    /// Commit any `SetKeymap`/`SetPixel` changes made since the last `Save` to flash. Issuing
+    /// one `Save` after a batch of edits avoids burning a flash erase cycle per message.
+    Save,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum AnimationKind {
+    Breathing,
+    ScrollText,
+}
+
+/// A reply sent from the keypad back to the host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DeviceMessage {
+    Ack,
+    Config { num_layers: usize },
+    KeymapEntry { layer: usize, index: usize, action: Action },
+    Pixel { index: usize, rgb: (u8, u8, u8) },
+    Error,
+}
+
+/// Decodes one COBS-framed `postcard` message. `frame` must be a single complete frame
+/// including its trailing zero delimiter; it is mutated in place by the COBS decode.
+pub fn decode_host_message(frame: &mut [u8]) -> Option<HostMessage> {
+    postcard::from_bytes_cobs(frame).ok()
+}
+
+/// COBS-frames and `postcard`-encodes a reply for transmission over the serial channel.
+pub fn encode_device_message(message: &DeviceMessage) -> Option<heapless::Vec<u8, MAX_FRAME_LEN>> {
+    postcard::to_vec_cobs(message).ok()
+}
+
+/// Reassembles COBS frames out of a byte stream that may split or coalesce them arbitrarily,
+/// e.g. across successive USB bulk reads.
+pub struct FrameReassembler {
+    buf: heapless::Vec<u8, MAX_FRAME_LEN>,
+}
+
+impl FrameReassembler {
+    pub const fn new() -> Self {
+        Self {
+            buf: heapless::Vec::new(),
+        }
+    }
+
+    /// Feeds newly-read bytes into the reassembly buffer, invoking `on_frame` with each
+    /// complete COBS frame (including its trailing zero delimiter) as it is found. If a frame
+    /// exceeds [`MAX_FRAME_LEN`] before a delimiter is seen, the partial data is dropped so the
+    /// reassembler can resync on the next delimiter.
+    pub fn feed(&mut self, data: &[u8], mut on_frame: impl FnMut(&mut [u8])) {
+        for &byte in data {
+            if self.buf.push(byte).is_err() {
+                // Oversized frame: discard its remaining bytes up to and including its
+                // delimiter, then start the next frame from an empty buffer.
+                if byte == 0 {
+                    self.buf.clear();
+                }
+                continue;
+            }
+
+            if byte == 0 {
+                on_frame(&mut self.buf);
+                self.buf.clear();
+            }
+        }
+    }
+}
+
+impl Default for FrameReassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}